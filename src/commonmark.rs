@@ -0,0 +1,218 @@
+// Copyright (C) 2018 Vincent Ambo <mail@tazj.in>
+//
+// nixdoc is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This module implements a partial CommonMark renderer for the
+//! `ManualEntry` structures collected from a Nix file.
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use serde::Deserialize;
+
+/// Location of an entry in its source file, as supplied by `--locs`.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SearchEntry {
+    /// Path of the file containing the entry, relative to the nixpkgs root.
+    pub file: String,
+
+    /// 1-based line number of the entry inside `file`.
+    pub line: usize,
+}
+
+/// Mapping of fully qualified names (e.g. `strings.concatStrings`) to
+/// their source location.
+pub type SearchResults = HashMap<String, SearchEntry>;
+
+/// A single function argument with its own, optional documentation.
+#[derive(Debug, Clone)]
+pub struct SingleArg {
+    pub name: String,
+    pub doc: Option<String>,
+
+    /// Whether this pattern entry has a default value (`c ? default`).
+    /// Always `false` for a flat, non-pattern argument.
+    pub has_default: bool,
+}
+
+/// A function argument as inferred from a lambda's parameter list. This
+/// can either be a flat identifier, or a pattern (`{ a, b, ... }`) with
+/// several sub-arguments.
+#[derive(Debug, Clone)]
+pub enum Argument {
+    /// Plain identifier argument, e.g. the `a` in `a: ...`.
+    Flat(SingleArg),
+
+    /// Pattern argument, e.g. the `{ b, c, ... }` in `{ b, c, ... }: ...`.
+    Pattern {
+        entries: Vec<SingleArg>,
+
+        /// Whether the pattern ends with `...`, accepting extra attributes.
+        ellipsis: bool,
+
+        /// The `@`-bound name, if any (e.g. `args` in `{ ... } @ args: ...`).
+        bind: Option<String>,
+    },
+}
+
+/// Processed documentation entry for a single library function, ready to
+/// be rendered as a CommonMark section.
+#[derive(Debug, Clone)]
+pub struct ManualEntry {
+    /// Name of the function category (e.g. 'strings', 'attrsets').
+    pub category: String,
+
+    /// Name of the entry (e.g. 'concatStrings', or 'strings.concatMapStrings'
+    /// for entries nested inside a sub-attrset).
+    pub name: String,
+
+    /// Type signature, if provided by the author. This is not an
+    /// actually-checked Nix type, but a free-form annotation.
+    pub fn_type: Option<String>,
+
+    /// Primary description of the entry, as a list of Markdown paragraphs.
+    pub description: Vec<String>,
+
+    /// Usage example(s) for the entry, rendered as a single code block.
+    pub example: Option<String>,
+
+    /// Arguments of the function, as collected from its lambda parameters.
+    pub args: Vec<Argument>,
+
+    /// Concise, pretty-printed call shape inferred from `args`, e.g.
+    /// `a → { b, c?, ... } → ...`. Shown in place of `fn_type` when the
+    /// author didn't provide an explicit `Type:` annotation.
+    pub signature: Option<String>,
+
+    /// Whether `description` comes from an RFC 145 `/** */` doc-string.
+    /// Such comments are already fully-formed CommonMark and must be
+    /// emitted verbatim, without the legacy `Type:`/`Example:` sections.
+    pub rfc145: bool,
+
+    /// 1-based line number of this entry in its source file, computed
+    /// directly from the AST. Used as a fallback when `--locs` doesn't
+    /// have an override for this entry.
+    pub line: Option<usize>,
+}
+
+impl ManualEntry {
+    /// Fully qualified identifier of this entry, used for anchors and links
+    /// (e.g. `lib.strings.concatStrings`).
+    fn ident(&self) -> String {
+        format!("lib.{}.{}", self.category, self.name)
+    }
+
+    /// Write a single section describing this entry as CommonMark. `file`
+    /// is the path of the source file this entry was extracted from, used
+    /// to render a "Located at" link when `locs` has no override for it.
+    pub fn write_section<W: Write>(
+        &self,
+        locs: &SearchResults,
+        file: &str,
+        writer: &mut W,
+    ) -> io::Result<()> {
+        let ident = self.ident();
+
+        writeln!(
+            writer,
+            "## `{}` {{#function-library-{}}}\n",
+            ident,
+            ident.replace('.', "-")
+        )?;
+
+        self.write_body(writer)?;
+
+        match locs.get(&ident) {
+            Some(loc) => writeln!(writer, "*Located at {}:{}.*\n", loc.file, loc.line)?,
+            None => {
+                if let Some(line) = self.line {
+                    writeln!(writer, "*Located at {}:{}.*\n", file, line)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write the descriptive body of this entry — type, description,
+    /// arguments and example — without the section header or source
+    /// location link. Shared between full manual sections, which prefix it
+    /// with a category-anchored header, and single-function lookups, which
+    /// have no category to anchor on.
+    pub fn write_body<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.rfc145 {
+            // RFC 145 doc-strings are already CommonMark; emit them as-is
+            // instead of re-wrapping them into the legacy sections below.
+            // The argument list and inferred signature are derived
+            // independently from the lambda pattern, so they're still
+            // rendered here. RFC 145 comments have no `Type:` annotation,
+            // so the inferred signature is the only header shown.
+            if let Some(sig) = &self.signature {
+                writeln!(writer, "**Signature**: `{}`\n", sig)?;
+            }
+
+            for paragraph in &self.description {
+                writeln!(writer, "{}\n", paragraph)?;
+            }
+
+            self.write_args(writer)?;
+        } else {
+            if let Some(t) = &self.fn_type {
+                writeln!(writer, "**Type**: `{}`\n", t)?;
+            } else if let Some(sig) = &self.signature {
+                writeln!(writer, "**Signature**: `{}`\n", sig)?;
+            }
+
+            for paragraph in &self.description {
+                writeln!(writer, "{}\n", paragraph)?;
+            }
+
+            self.write_args(writer)?;
+
+            if let Some(example) = &self.example {
+                writeln!(writer, "**Example**\n")?;
+                writeln!(writer, "```nix\n{}\n```\n", example)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_args<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.args.is_empty() {
+            return Ok(());
+        }
+
+        for arg in &self.args {
+            match arg {
+                Argument::Flat(a) => self.write_single_arg(writer, a)?,
+                Argument::Pattern { entries, .. } => {
+                    for a in entries {
+                        self.write_single_arg(writer, a)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_single_arg<W: Write>(&self, writer: &mut W, arg: &SingleArg) -> io::Result<()> {
+        writeln!(writer, "`{}`\n", arg.name)?;
+        if let Some(doc) = &arg.doc {
+            writeln!(writer, ": {}\n", doc)?;
+        }
+        Ok(())
+    }
+}
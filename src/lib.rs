@@ -0,0 +1,584 @@
+// Copyright (C) 2018 Vincent Ambo <mail@tazj.in>
+//
+// nixdoc is free software: you can redistribute it and/or modify it
+// under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! This crate extracts CommonMark documentation from a Nix file defining
+//! library functions, such as the files in `lib/` in the nixpkgs repository.
+//! It is used both by the `nixdoc` CLI, which renders a whole manual, and
+//! by callers that only want the documentation for a single function (e.g.
+//! an editor or REPL integration), via [`lookup_doc`] and
+//! [`document_lambda_at`].
+//!
+//! TODO:
+//! * figure out how to specify examples (& leading whitespace?!)
+
+pub mod commonmark;
+
+use self::commonmark::*;
+use rnix::{
+    ast::{AstToken, Attr, Attrpath, AttrpathValue, Comment, Expr, Inherit, Lambda, LetIn, Param},
+    SyntaxKind, SyntaxNode,
+};
+use rowan::{ast::AstNode, TextSize, WalkEvent};
+use textwrap::dedent;
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub struct DocComment {
+    /// Primary documentation string.
+    pub doc: String,
+
+    /// Optional type annotation for the thing being documented.
+    pub doc_type: Option<String>,
+
+    /// Usage example(s) (interpreted as a single code block)
+    pub example: Option<String>,
+
+    /// Whether this comment was written using RFC 145 `/** */` syntax. Such
+    /// comments are treated as CommonMark verbatim rather than being split
+    /// into the legacy `Type:`/`Example:` sections.
+    pub rfc145: bool,
+}
+
+/// A doc comment as found verbatim in the source, before parsing.
+enum RawDocComment {
+    /// A legacy `/* ... */` or adjacent `#`-line comment, using the ad-hoc
+    /// `Type:`/`Example:` convention handled by `parse_doc_comment`.
+    Legacy(String),
+
+    /// An RFC 145 `/** ... */` doc-string, whose body is CommonMark and
+    /// must be taken as-is.
+    Rfc145(String),
+}
+
+impl RawDocComment {
+    /// Unwrap into the raw comment text, discarding the RFC 145 tag. Used
+    /// where the distinction doesn't matter, e.g. for argument docs.
+    fn into_text(self) -> String {
+        match self {
+            RawDocComment::Legacy(s) => s,
+            RawDocComment::Rfc145(s) => s,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DocItem {
+    pub name: String,
+    pub comment: DocComment,
+    pub args: Vec<Argument>,
+
+    /// 1-based line number of the entry's `AttrpathValue` in its source
+    /// file, computed from the AST itself rather than an external
+    /// `--locs` JSON file.
+    pub line: Option<usize>,
+}
+
+impl DocItem {
+    fn into_entry(self, category: &str) -> ManualEntry {
+        let signature = render_signature(&self.args);
+
+        ManualEntry {
+            category: category.to_string(),
+            name: self.name,
+            description: self
+                .comment
+                .doc
+                .split("\n\n")
+                .map(|s| s.to_string())
+                .collect(),
+            fn_type: self.comment.doc_type,
+            example: self.comment.example,
+            args: self.args,
+            signature,
+            rfc145: self.comment.rfc145,
+            line: self.line,
+        }
+    }
+}
+
+/// Compute the 1-based line number that `offset` falls on within `src`.
+fn line_number(src: &str, offset: TextSize) -> usize {
+    src[..usize::from(offset)].matches('\n').count() + 1
+}
+
+/// Retrieve documentation comments.
+fn retrieve_doc_comment(node: &SyntaxNode, allow_line_comments: bool) -> Option<RawDocComment> {
+    // if the current node has a doc comment it'll be immediately preceded by that comment,
+    // or there will be a whitespace token and *then* the comment tokens before it. we merge
+    // multiple line comments into one large comment if they are on adjacent lines for
+    // documentation simplicity.
+    let mut token = node.first_token()?.prev_token()?;
+    if token.kind() == SyntaxKind::TOKEN_WHITESPACE {
+        token = token.prev_token()?;
+    }
+    if token.kind() != SyntaxKind::TOKEN_COMMENT {
+        return None;
+    }
+
+    // if we want to ignore line comments (eg because they may contain deprecation
+    // comments on attributes) we'll backtrack to the first preceding multiline comment.
+    while !allow_line_comments && token.text().starts_with('#') {
+        token = token.prev_token()?;
+        if token.kind() == SyntaxKind::TOKEN_WHITESPACE {
+            token = token.prev_token()?;
+        }
+        if token.kind() != SyntaxKind::TOKEN_COMMENT {
+            return None;
+        }
+    }
+
+    // RFC 145 doc-strings use a dedicated `/**` opener and are tagged
+    // separately so callers can skip the legacy Type:/Example: handling.
+    // The degenerate empty comment `/**/` (just 4 chars) also starts with
+    // `/**`, but it's the legacy empty comment, not an RFC 145 doc-string,
+    // so it's excluded here and falls through to the `/*` branch below.
+    if token.text().starts_with("/**") && token.text().len() > 4 {
+        let text = token
+            .text()
+            .strip_prefix("/**")?
+            .strip_suffix("*/")?
+            .to_string();
+        return Some(RawDocComment::Rfc145(text));
+    }
+
+    if token.text().starts_with("/*") {
+        return Some(RawDocComment::Legacy(
+            Comment::cast(token)?.text().to_string(),
+        ));
+    }
+
+    // backtrack to the start of the doc comment, allowing only adjacent line comments.
+    // we don't care much about optimization here, doc comments aren't long enough for that.
+    if token.text().starts_with('#') {
+        let mut result = String::new();
+        while let Some(comment) = Comment::cast(token) {
+            if !comment.syntax().text().starts_with('#') {
+                break;
+            }
+            result.insert_str(0, comment.text().trim());
+            let ws = match comment.syntax().prev_token() {
+                Some(t) if t.kind() == SyntaxKind::TOKEN_WHITESPACE => t,
+                _ => break,
+            };
+            // only adjacent lines continue a doc comment, empty lines do not.
+            match ws.text().strip_prefix('\n') {
+                Some(trail) if !trail.contains('\n') => result.insert(0, ' '),
+                _ => break,
+            }
+            token = match ws.prev_token() {
+                Some(c) => c,
+                _ => break,
+            };
+        }
+        return Some(RawDocComment::Legacy(result));
+    }
+
+    None
+}
+
+/// Join an attrpath's segments with '.', e.g. `foo.bar` for the attrpath
+/// in `foo.bar = ...;`. Returns `None` if any segment is a dynamic (`${...}`)
+/// or string (`"..."`) attr, which aren't meaningful as part of a lib
+/// function's namespaced name.
+fn attrpath_name(path: &Attrpath) -> Option<String> {
+    path.attrs()
+        .map(|attr| match attr {
+            Attr::Ident(ident) => Some(ident.to_string()),
+            Attr::Dynamic(_) | Attr::Str(_) => None,
+        })
+        .collect::<Option<Vec<_>>>()
+        .map(|segments| segments.join("."))
+}
+
+/// Transforms an AST node into a `DocItem` if it has a leading
+/// documentation comment.
+fn retrieve_doc_item(node: &AttrpathValue) -> Option<DocItem> {
+    let comment = retrieve_doc_comment(node.syntax(), false)?;
+    let item_name = attrpath_name(&node.attrpath()?)?;
+
+    let comment = match comment {
+        RawDocComment::Legacy(raw) => parse_doc_comment(&raw),
+        RawDocComment::Rfc145(raw) => DocComment {
+            doc: handle_indentation(&raw).unwrap_or_default(),
+            doc_type: None,
+            example: None,
+            rfc145: true,
+        },
+    };
+
+    Some(DocItem {
+        name: item_name,
+        comment,
+        args: vec![],
+        line: None,
+    })
+}
+
+/// Dedent everything but the first line, whose indentation gets fully removed all the time
+///
+/// A doc comment like this in Nix:
+///
+/// {
+///   /* foo is
+///   the value:
+///     10
+///   */
+///   foo = 10;
+/// }
+///
+/// The parser turns this into "foo is\n  the value:\n    10\n" where the first
+/// line has no leading indentation, but the rest do
+///
+/// To align all lines to the same indentation, while preserving the
+/// formatting, we dedent all but the first line, while stripping any potential
+/// indentation from the first line.
+fn handle_indentation(raw: &str) -> Option<String> {
+    let result: String = match raw.split_once('\n') {
+        Some((first, rest)) => format!("{}\n{}", first.trim(), dedent(rest)),
+        None => raw.into(),
+    };
+
+    Some(result.trim().to_owned()).filter(|s| !s.is_empty())
+}
+
+/// Dumb, mutable, hacky doc comment "parser".
+fn parse_doc_comment(raw: &str) -> DocComment {
+    enum ParseState {
+        Doc,
+        Type,
+        Example,
+    }
+
+    let mut state = ParseState::Doc;
+
+    // Split the string into three parts, docs, type and example
+    let mut doc_str = String::new();
+    let mut type_str = String::new();
+    let mut example_str = String::new();
+
+    for line in raw.split_inclusive('\n') {
+        let trimmed_line = line.trim();
+        if let Some(suffix) = trimmed_line.strip_prefix("Type:") {
+            state = ParseState::Type;
+            type_str.push_str(suffix);
+            type_str.push('\n');
+        } else if let Some(suffix) = trimmed_line.strip_prefix("Example:") {
+            state = ParseState::Example;
+            example_str.push_str(suffix);
+            example_str.push('\n');
+        } else {
+            match state {
+                ParseState::Doc => doc_str.push_str(line),
+                ParseState::Type => type_str.push_str(line),
+                ParseState::Example => example_str.push_str(line),
+            }
+        }
+    }
+
+    DocComment {
+        doc: handle_indentation(&doc_str).unwrap_or(String::new()),
+        doc_type: handle_indentation(&type_str),
+        example: handle_indentation(&example_str),
+        rfc145: false,
+    }
+}
+
+/// Traverse a Nix lambda and collect the identifiers of arguments
+/// until an unexpected AST node is encountered.
+fn collect_lambda_args(mut lambda: Lambda) -> Vec<Argument> {
+    let mut args = vec![];
+
+    loop {
+        match lambda.param().unwrap() {
+            Param::IdentParam(id) => {
+                args.push(Argument::Flat(SingleArg {
+                    name: id.to_string(),
+                    doc: retrieve_doc_comment(id.syntax(), true).map(RawDocComment::into_text),
+                    has_default: false,
+                }));
+            }
+            Param::Pattern(pat) => {
+                let entries: Vec<_> = pat
+                    .pat_entries()
+                    .map(|entry| SingleArg {
+                        name: entry.ident().unwrap().to_string(),
+                        doc: retrieve_doc_comment(entry.syntax(), true)
+                            .map(RawDocComment::into_text),
+                        has_default: entry.default().is_some(),
+                    })
+                    .collect();
+
+                args.push(Argument::Pattern {
+                    entries,
+                    ellipsis: pat.ellipsis_token().is_some(),
+                    bind: pat
+                        .pat_bind()
+                        .and_then(|b| b.ident())
+                        .map(|i| i.to_string()),
+                });
+            }
+        }
+
+        // Curried or not?
+        match lambda.body() {
+            Some(Expr::Lambda(inner)) => lambda = inner,
+            _ => break,
+        }
+    }
+
+    args
+}
+
+/// Render a single argument the way it appears in the lambda's parameter
+/// list, e.g. `a` for a flat identifier or `{ b, c?, ... } @ args` for a
+/// pattern with a defaulted entry, an open ellipsis and an `@`-binding.
+fn render_argument(arg: &Argument) -> String {
+    match arg {
+        Argument::Flat(a) => a.name.clone(),
+        Argument::Pattern {
+            entries,
+            ellipsis,
+            bind,
+        } => {
+            let mut parts: Vec<String> = entries
+                .iter()
+                .map(|e| {
+                    if e.has_default {
+                        format!("{}?", e.name)
+                    } else {
+                        e.name.clone()
+                    }
+                })
+                .collect();
+
+            if *ellipsis {
+                parts.push("...".to_string());
+            }
+
+            let pattern = format!("{{ {} }}", parts.join(", "));
+            match bind {
+                Some(name) => format!("{} @ {}", pattern, name),
+                None => pattern,
+            }
+        }
+    }
+}
+
+/// Pretty-print the call shape of a curried lambda from its collected
+/// arguments, e.g. `a: { b, c ? default, ... }: ...` becomes
+/// `a → { b, c?, ... } → ...`. Returns `None` for a niladic value, which
+/// has no arguments to render.
+fn render_signature(args: &[Argument]) -> Option<String> {
+    if args.is_empty() {
+        return None;
+    }
+
+    let mut parts: Vec<String> = args.iter().map(render_argument).collect();
+    parts.push("...".to_string());
+    Some(parts.join(" → "))
+}
+
+/// Traverse the arena from a top-level SetEntry and collect, where
+/// possible:
+///
+/// 1. The identifier of the set entry itself, qualified by `prefix` if the
+///    entry lives inside a nested attrset (e.g. `strings.concatStrings`).
+/// 2. The attached doc comment on the entry.
+/// 3. The arguments of any curried functions, including pattern
+///    arguments.
+/// 4. The line the entry's `AttrpathValue` starts on in `src`.
+fn collect_entry_information(entry: AttrpathValue, prefix: &str, src: &str) -> Option<DocItem> {
+    let mut doc_item = retrieve_doc_item(&entry)?;
+    if !prefix.is_empty() {
+        doc_item.name = format!("{}.{}", prefix, doc_item.name);
+    }
+    doc_item.line = Some(line_number(src, entry.syntax().text_range().start()));
+
+    if let Some(Expr::Lambda(l)) = entry.value() {
+        Some(DocItem {
+            args: collect_lambda_args(l),
+            ..doc_item
+        })
+    } else {
+        Some(doc_item)
+    }
+}
+
+/// Collect documented entries directly inside `n`, recursing into nested
+/// attrsets (e.g. `strings = { foo = …; }`) and accumulating their attrpath
+/// as a `.`-joined prefix so nested functions get names like
+/// `strings.foo` instead of colliding on the bare `foo`.
+fn collect_attr_set_entries(
+    n: &SyntaxNode,
+    prefix: &str,
+    scope: &HashMap<String, DocItem>,
+    src: &str,
+) -> Vec<DocItem> {
+    let mut entries = vec![];
+    for child in n.children() {
+        if let Some(apv) = AttrpathValue::cast(child.clone()) {
+            let nested_set = match apv.value() {
+                Some(Expr::AttrSet(set)) => Some(set),
+                _ => None,
+            };
+
+            match nested_set {
+                Some(set) => {
+                    // The namespace binding itself may carry its own doc
+                    // comment (e.g. `/* ... */ strings = { ... };`), which
+                    // is unrelated to the nested entries recursed into below.
+                    entries.extend(collect_entry_information(apv.clone(), prefix, src));
+
+                    if let Some(name) = apv.attrpath().and_then(|p| attrpath_name(&p)) {
+                        let nested_prefix = if prefix.is_empty() {
+                            name
+                        } else {
+                            format!("{}.{}", prefix, name)
+                        };
+                        entries.extend(collect_attr_set_entries(
+                            set.syntax(),
+                            &nested_prefix,
+                            scope,
+                            src,
+                        ));
+                    }
+                }
+                None => entries.extend(collect_entry_information(apv, prefix, src)),
+            }
+        } else if let Some(inh) = Inherit::cast(child) {
+            // `inherit (x) ...` needs much more handling than we can
+            // reasonably do here
+            if inh.from().is_some() {
+                continue;
+            }
+            entries.extend(
+                inh.attrs()
+                    .filter_map(|a| match a {
+                        Attr::Ident(i) => scope.get(&i.syntax().text().to_string()).cloned(),
+                        // ignore non-ident keys. these aren't useful as lib
+                        // functions in general anyway.
+                        _ => None,
+                    })
+                    .map(|mut di| {
+                        // inherited into a nested namespace, so it needs the
+                        // same prefixing as an entry defined directly here.
+                        if !prefix.is_empty() {
+                            di.name = format!("{}.{}", prefix, di.name);
+                        }
+                        di
+                    }),
+            );
+        }
+    }
+    entries
+}
+
+fn collect_doc_item_bindings(
+    node: &SyntaxNode,
+    scope: HashMap<String, DocItem>,
+    src: &str,
+) -> Vec<DocItem> {
+    for ev in node.preorder() {
+        match ev {
+            WalkEvent::Enter(n) if n.kind() == SyntaxKind::NODE_ATTR_SET => {
+                return collect_attr_set_entries(&n, "", &scope, src);
+            }
+            _ => (),
+        }
+    }
+
+    vec![]
+}
+
+/// Traverse a parsed Nix file and collect every documented item reachable
+/// from its top-level `let … in` or attribute set, without rendering them
+/// to any particular output format. `src` is the exact text `root` was
+/// parsed from, used to compute each entry's line number.
+fn collect_doc_items(root: &rnix::Root, src: &str) -> Vec<DocItem> {
+    // we will look into the top-level let and its body for function docs.
+    // we only need a single level of scope for this.
+    // since only the body can export a function we don't need to implement
+    // mutually recursive resolution.
+    for ev in root.syntax().preorder() {
+        match ev {
+            WalkEvent::Enter(n) if n.kind() == SyntaxKind::NODE_LET_IN => {
+                return collect_doc_item_bindings(
+                    LetIn::cast(n.clone()).unwrap().body().unwrap().syntax(),
+                    n.children()
+                        .filter_map(AttrpathValue::cast)
+                        .filter_map(|apv| collect_entry_information(apv, "", src))
+                        .map(|di| (di.name.clone(), di))
+                        .collect(),
+                    src,
+                );
+            }
+            WalkEvent::Enter(n) if n.kind() == SyntaxKind::NODE_ATTR_SET => {
+                return collect_doc_item_bindings(&n, Default::default(), src);
+            }
+            _ => (),
+        }
+    }
+
+    vec![]
+}
+
+/// Traverse a parsed Nix file and collect every documented item, rendered
+/// as a [`ManualEntry`] for the given function category.
+pub fn collect_entries(root: rnix::Root, category: &str) -> Vec<ManualEntry> {
+    // the green tree underlying `root` retains the exact source text, so
+    // line numbers can be computed without re-reading the file.
+    let src = root.syntax().text().to_string();
+    collect_doc_items(&root, &src)
+        .into_iter()
+        .map(|di| di.into_entry(category))
+        .collect()
+}
+
+/// Look up the documentation for a single function by its attribute path,
+/// e.g. `["strings", "concatMapStrings"]`. Returns `None` if the file
+/// doesn't parse, or no documented item matches that path.
+///
+/// This is meant for editor/REPL integrations that want the doc comment
+/// and inferred argument list for exactly one lambda, rather than a whole
+/// rendered manual.
+pub fn lookup_doc(src: &str, path: &[&str]) -> Option<DocItem> {
+    let root = rnix::Root::parse(src).ok()?;
+    let target = path.join(".");
+    collect_doc_items(&root, src)
+        .into_iter()
+        .find(|item| item.name == target)
+}
+
+/// Render the documentation for the lambda whose `AttrpathValue` contains
+/// `byte_offset`, e.g. the cursor position in an editor. Returns the
+/// rendered Markdown for that single entry, or `None` if no documented
+/// attribute contains that offset. A documented non-function value is
+/// rendered too, just without arguments or a signature.
+pub fn document_lambda_at(src: &str, byte_offset: usize) -> Option<String> {
+    let root = rnix::Root::parse(src).ok()?;
+    let offset = TextSize::try_from(byte_offset).ok()?;
+    let apv = root
+        .syntax()
+        .token_at_offset(offset)
+        .right_biased()?
+        .parent_ancestors()
+        .find_map(AttrpathValue::cast)?;
+    let item = collect_entry_information(apv, "", src)?;
+
+    let mut body = Vec::new();
+    item.into_entry("").write_body(&mut body).ok()?;
+    String::from_utf8(body).ok()
+}